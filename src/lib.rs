@@ -1,20 +1,147 @@
 #![doc = include_str!("../README.md")]
 
 
+//! ### Parallel reading
+//! This crate only provides the write half. As of the pinned `bevy_ecs` version,
+//!  [`MessageReader::par_read`](bevy_ecs::message::MessageReader::par_read) already returns a
+//!  `MessageParIter` that splits unread messages across the `ComputeTaskPool` with configurable
+//!  batching and advances the reader's cursor up front, so there is nothing to add here:
+//! ```rust
+//! fn parallel_message_system(mut reader : MessageReader<Supersonic>) {
+//!     reader.par_read().for_each(|message| {
+//!         info!("{:?} went supersonic!", message.entity);
+//!     });
+//! }
+//! ```
+
+#[cfg(feature = "persistence")]
+pub mod persistence;
+
 use bevy_ecs::{
     component::Tick,
-    message::{ Message, Messages },
+    entity::Entities,
+    message::Message,
     query::FilteredAccessSet,
     system::{
+        Commands,
         SystemParam,
         SystemMeta
     },
     world::{
+        CommandQueue,
         World,
         unsafe_world_cell::UnsafeWorldCell
     }
 };
+#[cfg(not(feature = "deterministic"))]
+use bevy_ecs::message::Messages;
 use bevy_utils::Parallel;
+#[cfg(feature = "deterministic")]
+use std::sync::atomic::{ AtomicU64, Ordering };
+
+
+/// The [`SystemParam::State`] of a [`ParallelMessageWriter`].
+///
+/// Without the `deterministic` feature this is simply a per-thread [`Messages`] buffer. With the
+///  `deterministic` feature enabled, each buffered `message` is additionally tagged with a
+///  sequence number so that [`ParallelMessageWriter`]'s flush can undo reordering introduced
+///  by which thread's buffer is drained first (not thread-scheduling nondeterminism within a flush).
+#[cfg(not(feature = "deterministic"))]
+pub type ParallelMessageWriterState<E> = Parallel<Messages<E>>;
+
+/// The [`SystemParam::State`] of a [`ParallelMessageWriter`].
+///
+/// Without the `deterministic` feature this is simply a per-thread [`Messages`](bevy_ecs::message::Messages)
+///  buffer. With the `deterministic` feature enabled, each buffered `message` is additionally
+///  tagged with a sequence number so that [`ParallelMessageWriter`]'s flush can undo reordering
+///  introduced by which thread's buffer is drained first (not thread-scheduling nondeterminism
+///  within a flush).
+#[cfg(feature = "deterministic")]
+pub struct ParallelMessageWriterState<E>
+where
+    E : Message
+{
+    buffer   : Parallel<Vec<(u64, E)>>,
+    sequence : AtomicU64
+}
+
+#[inline]
+#[cfg(not(feature = "deterministic"))]
+pub(crate) fn new_message_writer_state<E : Message>() -> ParallelMessageWriterState<E> {
+    Parallel::default()
+}
+
+#[inline]
+#[cfg(feature = "deterministic")]
+pub(crate) fn new_message_writer_state<E : Message>() -> ParallelMessageWriterState<E> {
+    ParallelMessageWriterState {
+        buffer   : Parallel::default(),
+        sequence : AtomicU64::new(0)
+    }
+}
+
+#[cfg(not(feature = "deterministic"))]
+pub(crate) fn write_message_impl<E : Message>(state : &ParallelMessageWriterState<E>, message : E) {
+    _ = state.scope(|e| e.send(message));
+}
+
+#[cfg(feature = "deterministic")]
+pub(crate) fn write_message_impl<E : Message>(state : &ParallelMessageWriterState<E>, message : E) {
+    let sequence = state.sequence.fetch_add(1, Ordering::Relaxed);
+    state.buffer.scope(|buffer| buffer.push((sequence, message)));
+}
+
+#[cfg(not(feature = "deterministic"))]
+pub(crate) fn write_message_batch_impl<E : Message>(state : &ParallelMessageWriterState<E>, messages : impl IntoIterator<Item = E>) {
+    _ = state.scope(|e| e.send_batch(messages));
+}
+
+#[cfg(feature = "deterministic")]
+pub(crate) fn write_message_batch_impl<E : Message>(state : &ParallelMessageWriterState<E>, messages : impl IntoIterator<Item = E>) {
+    state.buffer.scope(|buffer| {
+        buffer.extend(messages.into_iter().map(|message| {
+            (state.sequence.fetch_add(1, Ordering::Relaxed), message)
+        }));
+    });
+}
+
+#[cfg(not(feature = "deterministic"))]
+fn flush_message_writer_state<E : Message>(state : &mut ParallelMessageWriterState<E>, world : &mut World) {
+    world.write_message_batch(state.iter_mut().flat_map(|e| e.update_drain()));
+}
+
+/// Drains every thread's buffered `(sequence, message)` pairs, sorts them back into sequence
+///  order, and writes the now-ordered `message`s into the [`World`]. This undoes reordering
+///  introduced by thread-local buffer drain order, not by the underlying `fetch_add` race itself
+///  (see the note on [`ParallelMessageWriter`]). The sequence counter is reset afterwards so it
+///  does not grow unbounded across a long-running app.
+#[cfg(feature = "deterministic")]
+fn flush_message_writer_state<E : Message>(state : &mut ParallelMessageWriterState<E>, world : &mut World) {
+    world.write_message_batch(drain_message_writer_state(state));
+}
+
+/// Drains every thread's buffered messages into a single `Vec`, in the same order
+///  [`flush_message_writer_state`] would write them into the [`World`]. Used by the `persistence`
+///  feature's write-ahead writer to log a batch before it reaches the [`World`], rather than
+///  writing it straight in.
+#[cfg(not(feature = "deterministic"))]
+#[cfg_attr(not(feature = "persistence"), allow(dead_code))]
+pub(crate) fn drain_message_writer_state<E : Message>(state : &mut ParallelMessageWriterState<E>) -> Vec<E> {
+    state.iter_mut().flat_map(|e| e.update_drain()).collect()
+}
+
+/// Drains every thread's buffered `(sequence, message)` pairs into a single `Vec`, sorted back
+///  into sequence order (see the note on [`flush_message_writer_state`]), and resets the sequence
+///  counter. Used by the `persistence` feature's write-ahead writer to log a batch before it
+///  reaches the [`World`], rather than writing it straight in.
+#[cfg(feature = "deterministic")]
+#[cfg_attr(not(feature = "persistence"), allow(dead_code))]
+pub(crate) fn drain_message_writer_state<E : Message>(state : &mut ParallelMessageWriterState<E>) -> Vec<E> {
+    let mut ordered : Vec<(u64, E)> = state.buffer.iter_mut().flat_map(|buffer| buffer.drain(..)).collect();
+    ordered.sort_unstable_by_key(|(sequence, _)| *sequence);
+    state.sequence.store(0, Ordering::Relaxed);
+    ordered.into_iter().map(|(_, message)| message).collect()
+}
 
 
 /// An alternative to [`MessageWriter`](bevy_ecs::message::MessageWriter) that can be used in parallel
@@ -22,7 +149,14 @@ use bevy_utils::Parallel;
 ///
 /// ### Note
 /// Because send application order will depend on how many threads are ran, non-commutative sends
-///  may result in non-deterministic results.
+///  may result in non-deterministic results. Enabling the `deterministic` feature removes the
+///  extra nondeterminism introduced by which thread's local buffer happens to get drained first;
+///  it does *not* make flushes reproducible across separate runs. Sequence numbers are still
+///  assigned via a racing `fetch_add` as `write`/`write_batch` calls actually happen, so which of
+///  two concurrent writers (on different threads) gets the lower sequence is itself a product of
+///  OS thread scheduling, and can differ between runs of the same `par_iter` system. If you need
+///  true cross-run reproducibility, derive the order key from something stable instead, such as
+///  the query item's index.
 ///
 /// ### Example
 /// ```rust
@@ -41,7 +175,7 @@ pub struct ParallelMessageWriter<'state, E>
 where
     E : Message
 {
-    messages : &'state Parallel<Messages<E>>
+    state : &'state ParallelMessageWriterState<E>
 }
 
 impl<E> ParallelMessageWriter<'_, E>
@@ -53,10 +187,10 @@ where
     ///  Unlike [`MessageWriter::write`](bevy_ecs::message::MessageWriter::write), this method does not
     ///  return the [ID](bevy_ecs::message::MessageId) of the written `message`.
     ///
-    /// See [`Messages`] for details.
+    /// See [`Messages`](bevy_ecs::message::Messages) for details.
     #[inline]
     pub fn write(&self, message : E) {
-        _ = self.messages.scope(|e| e.send(message));
+        write_message_impl(self.state, message);
     }
 
     /// Sends a list of `message`s all at once, which can later be read by
@@ -64,22 +198,22 @@ where
     ///  individually. Unlike [`MessageWriter::write_batch`](bevy_ecs::message::MessageWriter::write_batch),
     ///  this method does not return the [IDs](bevy_ecs::message::MessageId) of the written `message`s.
     ///
-    /// See [`Messages`] for details.
+    /// See [`Messages`](bevy_ecs::message::Messages) for details.
     #[inline]
     pub fn write_batch(&self, messages : impl IntoIterator<Item = E>) {
-        _ = self.messages.scope(|e| e.send_batch(messages));
+        write_message_batch_impl(self.state, messages);
     }
 
     /// Writes the default value of the `message`. Useful when the message is an empty struct. Unlike
     ///  Unlike [`MessageWriter::write_default`](bevy_ecs::message::MessageWriter::write_default), this method
     ///  does not return the [IDs](bevy_ecs::message::MessageId) of the written `message`s.
     ///
-    /// See [`Messages`] for details.
+    /// See [`Messages`](bevy_ecs::message::Messages) for details.
     #[inline]
     pub fn write_default(&self)
     where
         E : Default
-    { _ = self.messages.scope(|e| e.send_default()); }
+    { write_message_impl(self.state, E::default()); }
 
 }
 
@@ -88,14 +222,14 @@ unsafe impl<E> SystemParam for ParallelMessageWriter<'_, E>
 where
     E : Message
 {
-    type State                = Parallel<Messages<E>>;
+    type State                = ParallelMessageWriterState<E>;
     type Item<'world, 'state> = ParallelMessageWriter<'state, E>;
 
     #[inline]
     fn init_state(
         _ : &mut World
     ) -> Self::State {
-        Parallel::default()
+        new_message_writer_state()
     }
 
     #[inline]
@@ -114,7 +248,140 @@ where
         _     : Tick,
     ) -> Self::Item<'world, 'state> {
         ParallelMessageWriter {
-            messages : state
+            state
+        }
+    }
+
+    fn apply(
+        state : &mut Self::State,
+        _     : &SystemMeta,
+        world : &mut World
+    ) {
+        flush_message_writer_state(state, world);
+    }
+
+}
+
+
+/// The [`SystemParam::State`] of a [`ParallelWriter`].
+pub struct ParallelWriterState<E>
+where
+    E : Message
+{
+    messages : ParallelMessageWriterState<E>,
+    commands : Parallel<CommandQueue>
+}
+
+
+/// Combines a [`ParallelMessageWriter`] with a [`ParallelCommands`](bevy_ecs::system::ParallelCommands)-style
+///  per-thread [`Commands`] queue, so a single `par_iter` closure can both send messages *and*
+///  queue structural changes (spawning, inserting, despawning) without splitting the work across
+///  two systems.
+///
+/// ### Example
+/// ```rust
+/// fn parallel_collision_system(
+///     query      : Query<(Entity, &Transform)>,
+///     par_writer : ParallelWriter<Collision>
+/// ) {
+///     query.par_iter().for_each(|(entity, transform)| {
+///         if transform.translation.y < 0.0 {
+///             par_writer.write(Collision { entity });
+///             par_writer.command_scope(|mut commands| {
+///                 commands.entity(entity).despawn();
+///             });
+///         }
+///     });
+/// }
+/// ```
+pub struct ParallelWriter<'world, 'state, E>
+where
+    E : Message
+{
+    messages : &'state ParallelMessageWriterState<E>,
+    commands : &'state Parallel<CommandQueue>,
+    entities : &'world Entities
+}
+
+impl<E> ParallelWriter<'_, '_, E>
+where
+    E : Message
+{
+
+    /// Writes an `message`, which can later be read by [`MessageReader`](bevy_ecs::message::MessageReader)s.
+    ///
+    /// See [`ParallelMessageWriter::write`] for details.
+    #[inline]
+    pub fn write(&self, message : E) {
+        write_message_impl(self.messages, message);
+    }
+
+    /// Sends a list of `message`s all at once, which can later be read by
+    ///  [`MessageReader`](bevy_ecs::message::MessageReader)s.
+    ///
+    /// See [`ParallelMessageWriter::write_batch`] for details.
+    #[inline]
+    pub fn write_batch(&self, messages : impl IntoIterator<Item = E>) {
+        write_message_batch_impl(self.messages, messages);
+    }
+
+    /// Writes the default value of the `message`. Useful when the message is an empty struct.
+    ///
+    /// See [`ParallelMessageWriter::write_default`] for details.
+    #[inline]
+    pub fn write_default(&self)
+    where
+        E : Default
+    { write_message_impl(self.messages, E::default()); }
+
+    /// Provides a scoped [`Commands`] instance backed by this thread's [`CommandQueue`], which is
+    ///  applied to the [`World`] once this system finishes running. Mirrors
+    ///  [`ParallelCommands::command_scope`](bevy_ecs::system::ParallelCommands::command_scope).
+    #[inline]
+    pub fn command_scope<R>(&self, func : impl FnOnce(Commands) -> R) -> R {
+        let mut commands = self.commands.borrow_local_mut();
+        func(Commands::new_from_entities(&mut commands, self.entities))
+    }
+
+}
+
+
+unsafe impl<E> SystemParam for ParallelWriter<'_, '_, E>
+where
+    E : Message
+{
+    type State                = ParallelWriterState<E>;
+    type Item<'world, 'state> = ParallelWriter<'world, 'state, E>;
+
+    #[inline]
+    fn init_state(
+        world : &mut World
+    ) -> Self::State {
+        ParallelWriterState {
+            messages : new_message_writer_state(),
+            commands : Parallel::default()
+        }
+    }
+
+    #[inline]
+    fn init_access(
+        state                : &Self::State,
+        system_meta          : &mut SystemMeta,
+        component_access_set : &mut FilteredAccessSet,
+        world                : &mut World
+    ) { }
+
+    #[inline]
+    unsafe fn get_param<'world, 'state>(
+        state : &'state mut Self::State,
+        _     : &SystemMeta,
+        world : UnsafeWorldCell<'world>,
+        _     : Tick,
+    ) -> Self::Item<'world, 'state> {
+        ParallelWriter {
+            messages : &state.messages,
+            commands : &state.commands,
+            entities : world.entities()
         }
     }
 
@@ -123,7 +390,10 @@ where
         _     : &SystemMeta,
         world : &mut World
     ) {
-        world.write_message_batch(state.iter_mut().flat_map(|e| e.update_drain()));
+        flush_message_writer_state(&mut state.messages, world);
+        for commands in state.commands.iter_mut() {
+            commands.apply(world);
+        }
     }
 
 }
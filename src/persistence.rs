@@ -0,0 +1,357 @@
+//! Write-ahead journaling of flushed message batches, for crash recovery and deterministic replay.
+//!
+//! This module is gated behind the `persistence` feature. [`PersistentParallelMessageWriter`] is a
+//!  drop-in alternative to [`ParallelMessageWriter`](crate::ParallelMessageWriter): its flush appends
+//!  the batch to a [`MessageWal`] *before* writing it into the [`World`](bevy_ecs::world::World), so
+//!  a batch that later systems this same frame react to has already been handed to the log file
+//!  (and, with [`Config::fsync_per_batch`], synced to disk). It is a
+//!  separate type, rather than persistence being wired into `ParallelMessageWriter::apply` directly,
+//!  so that the extra `Serialize`/`DeserializeOwned` bound only applies to apps that opt into it.
+//!
+//! ### Example
+//! ```rust
+//! app.insert_resource(MessageWal::<Supersonic>::open(Config::new("logs/supersonic")).unwrap());
+//!
+//! fn parallel_message_system(
+//!     mut query  : Query<(Entity, &Velocity)>,
+//!     par_writer : PersistentParallelMessageWriter<Supersonic>
+//! ) {
+//!     query.par_iter().for_each(|(entity, velocity)| {
+//!         if velocity.magnitude() > 343.2 {
+//!             par_writer.write(Supersonic { entity });
+//!         }
+//!     });
+//! }
+//! ```
+
+use std::{
+    fs::{ File, OpenOptions, create_dir_all, read_dir },
+    io::{ self, BufReader, BufWriter, Read, Write },
+    marker::PhantomData,
+    path::{ Path, PathBuf }
+};
+use bevy_ecs::{
+    component::Tick,
+    message::{ Message, Messages },
+    query::FilteredAccessSet,
+    system::{ SystemParam, SystemMeta },
+    world::{ World, unsafe_world_cell::UnsafeWorldCell }
+};
+use serde::{ Serialize, Deserialize, de::DeserializeOwned };
+use crate::{
+    ParallelMessageWriterState,
+    new_message_writer_state,
+    write_message_impl,
+    write_message_batch_impl,
+    drain_message_writer_state
+};
+
+
+/// Configures where and how a [`MessageWal`] persists flushed batches.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Directory that log segments are written into and read from. Created if missing.
+    pub directory          : PathBuf,
+    /// A new segment file is started before an appended batch would take the active segment past
+    ///  this many bytes. An empty segment takes any batch, however large.
+    pub max_segment_bytes  : u64,
+    /// Whether to `fsync` the segment file after every appended batch, trading throughput for
+    ///  the guarantee that a crash cannot lose an already-appended batch.
+    pub fsync_per_batch    : bool
+}
+
+impl Config {
+
+    /// Creates a `Config` that logs into `directory`, with a 64 MiB segment size and no per-batch
+    ///  `fsync`.
+    pub fn new(directory : impl Into<PathBuf>) -> Self {
+        Self {
+            directory         : directory.into(),
+            max_segment_bytes : 64 * 1024 * 1024,
+            fsync_per_batch   : false
+        }
+    }
+
+    /// Sets the segment rollover size, in bytes.
+    pub fn max_segment_bytes(mut self, max_segment_bytes : u64) -> Self {
+        self.max_segment_bytes = max_segment_bytes;
+        self
+    }
+
+    /// Sets whether every appended batch is immediately `fsync`ed.
+    pub fn fsync_per_batch(mut self, fsync_per_batch : bool) -> Self {
+        self.fsync_per_batch = fsync_per_batch;
+        self
+    }
+
+}
+
+
+/// A single persisted batch: the flattened messages that were flushed together, alongside an
+///  application-supplied `batch_state` (for example a tick or frame number) that records when the
+///  batch was originally produced. [`load_into_messages`] decodes it but does not return it.
+#[derive(Serialize, Deserialize)]
+struct Record<E, S> {
+    batch_state : S,
+    messages    : Vec<E>
+}
+
+/// The borrowing counterpart of [`Record`], used to encode a batch without first cloning it, since
+///  the same `messages` also need to be written into the [`World`] afterwards.
+#[derive(Serialize)]
+struct RecordRef<'a, E, S> {
+    batch_state : &'a S,
+    messages    : &'a [E]
+}
+
+
+/// A [`Resource`](bevy_ecs::resource::Resource) that appends flushed `message` batches to an
+///  on-disk write-ahead log, one length-prefixed [`Record`] per batch.
+///
+/// Open one with [`MessageWal::open`] and insert it into the [`World`](bevy_ecs::world::World) as
+///  a resource before any system using a [`PersistentParallelMessageWriter<E>`] runs. `S` is the
+///  type of the per-batch correlation value described on [`Record`]; it defaults to `()` for
+///  callers that don't need one.
+#[derive(bevy_ecs::resource::Resource)]
+pub struct MessageWal<E, S = ()>
+where
+    E : Message + Serialize + DeserializeOwned,
+    S : Serialize + DeserializeOwned + Send + Sync + 'static
+{
+    config        : Config,
+    segment       : BufWriter<File>,
+    segment_index : u64,
+    segment_bytes : u64,
+    _marker       : PhantomData<fn() -> (E, S)>
+}
+
+impl<E, S> MessageWal<E, S>
+where
+    E : Message + Serialize + DeserializeOwned,
+    S : Serialize + DeserializeOwned + Send + Sync + 'static
+{
+
+    /// Opens the log directory described by `config`, creating it if necessary and appending to
+    ///  its newest segment (or starting a fresh one if the directory is empty).
+    pub fn open(config : Config) -> io::Result<Self> {
+        create_dir_all(&config.directory)?;
+        let segment_index        = latest_segment_index(&config.directory)?;
+        let segment_path         = segment_path(&config.directory, segment_index);
+        let segment_bytes        = segment_path.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+        let segment              = BufWriter::new(OpenOptions::new().create(true).append(true).open(&segment_path)?);
+        Ok(Self { config, segment, segment_index, segment_bytes, _marker : PhantomData })
+    }
+
+    /// Appends one flushed `messages` batch, tagged with `batch_state`, as a length-prefixed
+    ///  record. Rolls over to a new segment first if this batch would take a non-empty segment past
+    ///  `max_segment_bytes`.
+    ///
+    /// Takes `messages` by reference, rather than by value, so a caller can still write the same
+    ///  batch into the [`World`] afterwards without needing `E: Clone`.
+    pub fn append_batch(&mut self, messages : &[E], batch_state : S) -> io::Result<()> {
+        let encoded = bincode::serialize(&RecordRef { batch_state : &batch_state, messages }).map_err(io::Error::other)?;
+        if (self.segment_bytes > 0) && (self.segment_bytes + 8 + (encoded.len() as u64) > self.config.max_segment_bytes) {
+            self.roll_segment()?;
+        }
+        self.segment.write_all(&(encoded.len() as u64).to_le_bytes())?;
+        self.segment.write_all(&encoded)?;
+        self.segment.flush()?;
+        if self.config.fsync_per_batch {
+            self.segment.get_ref().sync_data()?;
+        }
+        self.segment_bytes += 8 + (encoded.len() as u64);
+        Ok(())
+    }
+
+    fn roll_segment(&mut self) -> io::Result<()> {
+        self.segment.flush()?;
+        self.segment_index += 1;
+        self.segment_bytes  = 0;
+        self.segment        = BufWriter::new(
+            OpenOptions::new().create(true).append(true).open(segment_path(&self.config.directory, self.segment_index))?
+        );
+        Ok(())
+    }
+
+}
+
+
+/// A [`Resource`](bevy_ecs::resource::Resource) holding the `batch_state` that
+///  [`PersistentParallelMessageWriter<E, S>`] passes to [`MessageWal::append_batch`] for every
+///  batch it flushes, until the app updates it to a new value (for example, the current tick or
+///  frame count, each `Update`). If absent when a flush happens, `S::default()` is used instead.
+#[derive(bevy_ecs::resource::Resource)]
+pub struct BatchCorrelation<S>(pub S);
+
+/// An alternative to [`ParallelMessageWriter`](crate::ParallelMessageWriter) that appends each
+///  flushed batch to a [`MessageWal<E, S>`] *before* writing it into the [`World`](bevy_ecs::world::World),
+///  making the log a true write-*ahead* log rather than a write-*behind* one: nothing scheduled
+///  after this [`SystemParam`] flushes can observe a batch that hasn't already been appended to
+///  the log (synced to disk only when [`Config::fsync_per_batch`] is set).
+///
+/// A [`MessageWal<E, S>`] resource must already be inserted into the `World` (see
+///  [`MessageWal::open`]) before a system using this parameter runs; [`apply`](SystemParam::apply)
+///  silently skips logging (but still writes the batch into the `World`) if it isn't found. Each
+///  batch is tagged with whatever [`BatchCorrelation<S>`] currently holds (or `S::default()` if
+///  that resource hasn't been inserted); see [`MessageWal`]'s `S` for what that's for.
+///
+/// ### Panics
+/// Panics if the [`MessageWal<E, S>`] fails to append the batch (for example, a full disk, or a
+///  permission error), rather than letting messages reach the rest of the frame having silently
+///  failed to become durable.
+pub struct PersistentParallelMessageWriter<'state, E, S = ()>
+where
+    E : Message + Serialize + DeserializeOwned,
+    S : Serialize + DeserializeOwned + Clone + Default + Send + Sync + 'static
+{
+    state   : &'state ParallelMessageWriterState<E>,
+    _marker : PhantomData<fn() -> S>
+}
+
+impl<E, S> PersistentParallelMessageWriter<'_, E, S>
+where
+    E : Message + Serialize + DeserializeOwned,
+    S : Serialize + DeserializeOwned + Clone + Default + Send + Sync + 'static
+{
+
+    /// Writes a `message`, which can later be read by [`MessageReader`](bevy_ecs::message::MessageReader)s.
+    ///
+    /// See [`ParallelMessageWriter::write`](crate::ParallelMessageWriter::write) for details.
+    #[inline]
+    pub fn write(&self, message : E) {
+        write_message_impl(self.state, message);
+    }
+
+    /// Sends a list of `message`s all at once, which can later be read by
+    ///  [`MessageReader`](bevy_ecs::message::MessageReader)s.
+    ///
+    /// See [`ParallelMessageWriter::write_batch`](crate::ParallelMessageWriter::write_batch) for details.
+    #[inline]
+    pub fn write_batch(&self, messages : impl IntoIterator<Item = E>) {
+        write_message_batch_impl(self.state, messages);
+    }
+
+    /// Writes the default value of the `message`. Useful when the message is an empty struct.
+    ///
+    /// See [`ParallelMessageWriter::write_default`](crate::ParallelMessageWriter::write_default) for details.
+    #[inline]
+    pub fn write_default(&self)
+    where
+        E : Default
+    { write_message_impl(self.state, E::default()); }
+
+}
+
+unsafe impl<E, S> SystemParam for PersistentParallelMessageWriter<'_, E, S>
+where
+    E : Message + Serialize + DeserializeOwned,
+    S : Serialize + DeserializeOwned + Clone + Default + Send + Sync + 'static
+{
+    type State                = ParallelMessageWriterState<E>;
+    type Item<'world, 'state> = PersistentParallelMessageWriter<'state, E, S>;
+
+    #[inline]
+    fn init_state(
+        _ : &mut World
+    ) -> Self::State {
+        new_message_writer_state()
+    }
+
+    #[inline]
+    fn init_access(
+        state                : &Self::State,
+        system_meta          : &mut SystemMeta,
+        component_access_set : &mut FilteredAccessSet,
+        world                : &mut World
+    ) { }
+
+    #[inline]
+    unsafe fn get_param<'world, 'state>(
+        state : &'state mut Self::State,
+        _     : &SystemMeta,
+        _     : UnsafeWorldCell<'world>,
+        _     : Tick,
+    ) -> Self::Item<'world, 'state> {
+        PersistentParallelMessageWriter {
+            state,
+            _marker : PhantomData
+        }
+    }
+
+    fn apply(
+        state : &mut Self::State,
+        _     : &SystemMeta,
+        world : &mut World
+    ) {
+        let messages = drain_message_writer_state(state);
+        if !messages.is_empty() {
+            let batch_state = world.get_resource::<BatchCorrelation<S>>().map_or_else(S::default, |correlation| correlation.0.clone());
+            if let Some(mut wal) = world.get_resource_mut::<MessageWal<E, S>>() {
+                if let Err(error) = wal.append_batch(&messages, batch_state) {
+                    panic!("failed to append a batch to the write-ahead log at {:?}: {error}", wal.config.directory);
+                }
+            }
+            world.write_message_batch(messages);
+        }
+    }
+
+}
+
+
+/// Replays every batch persisted under `config.directory`, in the order they were written, into a
+///  fresh [`Messages`] buffer. Intended to be called once at startup, before the [`MessageWal`]
+///  for the same directory is opened for new writes, to recover messages that were flushed but
+///  never consumed before a crash.
+///
+/// Returns an empty `Messages` (rather than an error) if `config.directory` doesn't exist yet,
+///  which is the case on a fresh install with no prior `MessageWal`. Each record's `batch_state`
+///  is decoded as `S` and then dropped; only the messages are returned.
+pub fn load_into_messages<E, S>(config : &Config) -> io::Result<Messages<E>>
+where
+    E : Message + DeserializeOwned,
+    S : DeserializeOwned
+{
+    let mut messages = Messages::default();
+    for segment_index in 0 ..= latest_segment_index(&config.directory)? {
+        let path = segment_path(&config.directory, segment_index);
+        if !path.exists() {
+            continue;
+        }
+        let mut reader = BufReader::new(File::open(&path)?);
+        loop {
+            let mut len_bytes = [0u8; 8];
+            match reader.read_exact(&mut len_bytes) {
+                Ok(())                                              => {},
+                Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(error)                                          => return Err(error)
+            }
+            let mut encoded = vec![0u8; u64::from_le_bytes(len_bytes) as usize];
+            reader.read_exact(&mut encoded)?;
+            let record : Record<E, S> = bincode::deserialize(&encoded).map_err(io::Error::other)?;
+            messages.send_batch(record.messages);
+        }
+    }
+    Ok(messages)
+}
+
+
+fn segment_path(directory : &Path, segment_index : u64) -> PathBuf {
+    directory.join(format!("{segment_index:020}.wal"))
+}
+
+/// Returns the highest segment index present in `directory`, or `0` if `directory` doesn't exist
+///  yet (a fresh install has no segments to report).
+fn latest_segment_index(directory : &Path) -> io::Result<u64> {
+    let entries = match read_dir(directory) {
+        Ok(entries)                                           => entries,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(error)                                            => return Err(error)
+    };
+    let mut latest = 0;
+    for entry in entries {
+        if let Some(index) = entry?.file_name().to_str().and_then(|name| name.strip_suffix(".wal")).and_then(|name| name.parse().ok()) {
+            latest = latest.max(index);
+        }
+    }
+    Ok(latest)
+}